@@ -19,15 +19,41 @@ use riscv::paging::{
 use riscv::register::satp;
 
 #[cfg(target_arch = "riscv32")]
-pub struct ActivePageTable(usize, PageEntry);
+pub struct ActivePageTable(usize, Option<PageEntry>);
 
 #[cfg(target_arch = "riscv64")]
-pub struct ActivePageTable(RecursivePageTable<'static>, PageEntry);
+pub struct ActivePageTable(RecursivePageTable<'static>, Option<PageEntry>);
 
 /// PageTableEntry: the contents of this entry.
 /// Page: this entry is the pte of page `Page`.
 pub struct PageEntry(&'static mut PageTableEntry, Page);
 
+/// Size of a huge-page leaf for [`ActivePageTable::map_huge`] (Sv39/Sv48/Sv57).
+#[cfg(target_arch = "riscv64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugeSize {
+    /// 2 MiB superpage, a leaf at level 1.
+    Size2M,
+    /// 1 GiB gigapage, a leaf at level 2.
+    Size1G,
+}
+
+#[cfg(target_arch = "riscv64")]
+impl HugeSize {
+    fn bytes(self) -> usize {
+        match self {
+            HugeSize::Size2M => 1 << 21,
+            HugeSize::Size1G => 1 << 30,
+        }
+    }
+    fn leaf_level(self) -> usize {
+        match self {
+            HugeSize::Size2M => 1,
+            HugeSize::Size1G => 2,
+        }
+    }
+}
+
 impl PageTable for ActivePageTable {
     fn map(&mut self, addr: usize, target: usize) -> &mut Entry {
         // use riscv::paging:Mapper::map_to,
@@ -55,8 +81,8 @@ impl PageTable for ActivePageTable {
         let page = Page::of_addr(VirtAddr::new(vaddr));
         if let Ok(e) = self.get_table().ref_entry(page.clone()) {
             let e = unsafe { &mut *(e as *mut PageTableEntry) };
-            self.1 = PageEntry(e, page);
-            Some(&mut self.1 as &mut Entry)
+            self.1 = Some(PageEntry(e, page));
+            Some(self.1.as_mut().unwrap() as &mut Entry)
         } else {
             None
         }
@@ -86,6 +112,43 @@ impl PageTableExt for ActivePageTable {}
 
 static mut __page_table_with_mode: bool = false;
 
+/// Level of the root page table (0 is the 4 KiB leaf level), used to walk the
+/// tree by hand when installing huge-page leaves.
+#[cfg(all(target_arch = "riscv64", feature = "sv39"))]
+const ROOT_LEVEL: usize = 2;
+#[cfg(all(target_arch = "riscv64", feature = "sv57"))]
+const ROOT_LEVEL: usize = 4;
+#[cfg(all(target_arch = "riscv64", not(any(feature = "sv39", feature = "sv57"))))]
+const ROOT_LEVEL: usize = 3;
+
+/// Size of a 4 KiB page, in bytes.
+const PAGE_SIZE: usize = 1 << 12;
+
+/// Fixed scratch virtual page used by [`TemporaryPage`] to map an arbitrary
+/// physical frame while an inactive page table is being edited.
+#[cfg(target_arch = "riscv32")]
+const TEMPORARY_PAGE_ADDR: usize = 0xCAFE_B000;
+#[cfg(target_arch = "riscv64")]
+const TEMPORARY_PAGE_ADDR: usize = 0xFFFF_FFFF_CAFE_B000;
+
+/// Top of the virtual window from which [`ActivePageTable::map_stack`] hands
+/// out guarded thread stacks, growing downward.
+#[cfg(target_arch = "riscv32")]
+const STACK_WINDOW_TOP: usize = 0xFF00_0000;
+// must stay canonical for the active paging mode. The window grows downward, so
+// it has to sit near the *top* of the canonical high half with at least
+// STACK_WINDOW_SIZE of headroom above the canonical minimum, otherwise every
+// mapped VA would land in the non-canonical gap below the minimum.
+#[cfg(all(target_arch = "riscv64", feature = "sv39"))]
+const STACK_WINDOW_TOP: usize = 0xFFFF_FFFF_F000_0000;
+#[cfg(all(target_arch = "riscv64", feature = "sv57"))]
+const STACK_WINDOW_TOP: usize = 0xFFFF_FFFF_F000_0000;
+#[cfg(all(target_arch = "riscv64", not(any(feature = "sv39", feature = "sv57"))))]
+const STACK_WINDOW_TOP: usize = 0xFFFF_FF00_0000_0000;
+
+/// Number of bytes the stack window spans before it is exhausted.
+const STACK_WINDOW_SIZE: usize = 0x1000_0000;
+
 /// The virtual address of root page table
 #[cfg(all(target_arch = "riscv64", feature = "sv39"))]
 const ROOT_PAGE_TABLE: *mut RvPageTable = ((0xFFFF_0000_0000_0000)
@@ -93,7 +156,17 @@ const ROOT_PAGE_TABLE: *mut RvPageTable = ((0xFFFF_0000_0000_0000)
     | (RECURSIVE_INDEX << 12 << 9 << 9)
     | (RECURSIVE_INDEX << 12 << 9)
     | ((RECURSIVE_INDEX + 1) << 12)) as *mut RvPageTable;
-#[cfg(all(target_arch = "riscv64", not(feature = "sv39")))]
+#[cfg(all(target_arch = "riscv64", feature = "sv57"))]
+const ROOT_PAGE_TABLE: *mut RvPageTable = (
+    // sign-extend bits 57..63 from VPN[4]'s top bit (bit 56) instead of
+    // assuming RECURSIVE_INDEX >= 256
+    ((((RECURSIVE_INDEX >> 8) & 1) * 0x7F) << 57)
+    | (RECURSIVE_INDEX << 12 << 9 << 9 << 9 << 9)
+    | (RECURSIVE_INDEX << 12 << 9 << 9 << 9)
+    | (RECURSIVE_INDEX << 12 << 9 << 9)
+    | (RECURSIVE_INDEX << 12 << 9)
+    | ((RECURSIVE_INDEX + 1) << 12)) as *mut RvPageTable;
+#[cfg(all(target_arch = "riscv64", not(any(feature = "sv39", feature = "sv57"))))]
 const ROOT_PAGE_TABLE: *mut RvPageTable = ((0xFFFF_0000_0000_0000)
     | (RECURSIVE_INDEX << 12 << 9 << 9 << 9)
     | (RECURSIVE_INDEX << 12 << 9 << 9)
@@ -103,20 +176,19 @@ const ROOT_PAGE_TABLE: *mut RvPageTable = ((0xFFFF_0000_0000_0000)
 impl ActivePageTable {
     #[cfg(target_arch = "riscv32")]
     pub unsafe fn new() -> Self {
-        ActivePageTable(
-            get_root_page_table_ptr(),
-            ::core::mem::uninitialized(),
-        )
+        ActivePageTable(get_root_page_table_ptr(), None)
     }
     #[cfg(target_arch = "riscv64")]
     pub unsafe fn new() -> Self {
         #[cfg(feature = "sv39")]
         let type_ = PageTableType::Sv39;
-        #[cfg(not(feature = "sv39"))]
+        #[cfg(feature = "sv57")]
+        let type_ = PageTableType::Sv57;
+        #[cfg(not(any(feature = "sv39", feature = "sv57")))]
         let type_ = PageTableType::Sv48;
         ActivePageTable(
             RecursivePageTable::new(&mut *ROOT_PAGE_TABLE, type_).unwrap(),
-            ::core::mem::uninitialized(),
+            None,
         )
     }
 
@@ -131,6 +203,107 @@ impl ActivePageTable {
     fn get_table(&mut self) -> TwoLevelPageTable<'static> {
         unsafe { TwoLevelPageTable::new(&mut *self.get_raw_table(), LINEAR_OFFSET) }
     }
+
+    /// Install a huge-page leaf mapping `addr` to `target`. `addr`/`target`
+    /// must be `size`-aligned and the target entry currently unused.
+    #[cfg(target_arch = "riscv64")]
+    pub fn map_huge(&mut self, addr: usize, target: usize, size: HugeSize) -> Result<(), ()> {
+        let bytes = size.bytes();
+        if addr % bytes != 0 || target % bytes != 0 {
+            return Err(());
+        }
+        let entry = self.huge_entry(addr, size.leaf_level(), true).ok_or(())?;
+        if !entry.is_unused() {
+            return Err(());
+        }
+        let flags = EF::VALID | EF::READABLE | EF::WRITABLE;
+        entry.set(Frame::of_addr(PhysAddr::new(target)), flags);
+        unsafe {
+            sfence_vma(0, addr);
+        }
+        Ok(())
+    }
+
+    /// Remove a huge-page leaf previously installed by [`map_huge`].
+    #[cfg(target_arch = "riscv64")]
+    pub fn unmap_huge(&mut self, addr: usize, size: HugeSize) -> Result<(), ()> {
+        let entry = self.huge_entry(addr, size.leaf_level(), false).ok_or(())?;
+        if entry.is_unused() {
+            return Err(());
+        }
+        entry.set_unused();
+        unsafe {
+            sfence_vma(0, addr);
+        }
+        Ok(())
+    }
+
+    /// Allocate a `pages`-page thread stack with an unmapped guard page below
+    /// it, returning the top of the usable stack.
+    ///
+    /// Stacks are carved out of a fixed window by a monotonic bump that never
+    /// reclaims; it is not wired into the address-space allocator, so it panics
+    /// once the window is exhausted rather than colliding with other mappings.
+    pub fn map_stack(&mut self, pages: usize) -> usize {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        // hand out stacks downward from the top of a dedicated window
+        static STACK_TOP: AtomicUsize = AtomicUsize::new(STACK_WINDOW_TOP);
+        let bytes = (pages + 1) * PAGE_SIZE;
+        let bottom = STACK_TOP.fetch_sub(bytes, Ordering::SeqCst) - bytes;
+        assert!(
+            bottom >= STACK_WINDOW_TOP - STACK_WINDOW_SIZE,
+            "stack window exhausted"
+        );
+        // leave `bottom` unmapped as the guard page
+        let flags = EF::VALID | EF::READABLE | EF::WRITABLE;
+        for i in 1..=pages {
+            let addr = bottom + i * PAGE_SIZE;
+            let target = alloc_frame().expect("failed to allocate stack frame");
+            let page = Page::of_addr(VirtAddr::new(addr));
+            let frame = Frame::of_addr(PhysAddr::new(target));
+            self.get_table()
+                .map_to(page, frame, flags, &mut FrameAllocatorForRiscv)
+                .unwrap()
+                .flush();
+        }
+        bottom + bytes
+    }
+
+    /// Walk down to the entry at `target_level` that governs `addr`, allocating
+    /// and zeroing any missing intermediate tables when `alloc` is set.
+    #[cfg(target_arch = "riscv64")]
+    fn huge_entry(
+        &mut self,
+        addr: usize,
+        target_level: usize,
+        alloc: bool,
+    ) -> Option<&'static mut PageTableEntry> {
+        let mut table = unsafe { &mut *self.get_raw_table() };
+        let mut level = ROOT_LEVEL;
+        while level > target_level {
+            let index = (addr >> (12 + 9 * level)) & 0o777;
+            if table[index].is_unused() {
+                if !alloc {
+                    return None;
+                }
+                let frame = FrameAllocatorForRiscv.alloc()?;
+                table[index].set(frame.clone(), EF::VALID);
+                let next = (frame.start_address().as_usize() + LINEAR_OFFSET) as *mut RvPageTable;
+                unsafe { (*next).zero() };
+            } else if table[index]
+                .flags()
+                .intersects(EF::READABLE | EF::WRITABLE | EF::EXECUTABLE)
+            {
+                // a huge leaf already covers this range; don't walk into its data frame
+                return None;
+            }
+            let child = (table[index].addr().as_usize() + LINEAR_OFFSET) as *mut RvPageTable;
+            table = unsafe { &mut *child };
+            level -= 1;
+        }
+        let index = (addr >> (12 + 9 * target_level)) & 0o777;
+        Some(unsafe { &mut *(&mut table[index] as *mut PageTableEntry) })
+    }
 }
 
 /// implementation for the Entry trait in /crate/memory/src/paging/mod.rs
@@ -205,9 +378,73 @@ impl Entry for PageEntry {
         self.0.flags_mut().set(EF::EXECUTABLE, value);
     }
     fn mmio(&self) -> u8 {
-        0
+        // Svpbmt PBMT field, PTE bits 61..62; the field only exists in 64-bit
+        // PTEs and only on targets that actually implement Svpbmt. On any other
+        // target those bits are reserved-must-be-zero.
+        #[cfg(all(target_arch = "riscv64", feature = "svpbmt"))]
+        {
+            use bit_field::BitField;
+            self.0.bits().get_bits(61..63) as u8
+        }
+        #[cfg(not(all(target_arch = "riscv64", feature = "svpbmt")))]
+        {
+            0
+        }
+    }
+    fn set_mmio(&mut self, value: u8) {
+        #[cfg(all(target_arch = "riscv64", feature = "svpbmt"))]
+        {
+            use bit_field::BitField;
+            let mut bits = self.0.bits();
+            bits.set_bits(61..63, (value as usize) & 0b11);
+            *self.0 = PageTableEntry::new(bits);
+        }
+        // Without Svpbmt the PBMT bits are reserved-must-be-zero; leave the PTE
+        // untouched rather than writing a malformed entry.
+        #[cfg(not(all(target_arch = "riscv64", feature = "svpbmt")))]
+        {
+            let _ = value;
+        }
+    }
+}
+
+/// Scratch mapping for editing an inactive page table. Maps a physical frame
+/// to the fixed virtual address [`TEMPORARY_PAGE_ADDR`] and unmaps on drop.
+///
+/// There is a single scratch slot, so only one may be live at a time; `map`
+/// asserts this to catch a nested `edit`/`new_bare` clobbering the slot.
+struct TemporaryPage {
+    addr: usize,
+}
+
+static TEMPORARY_PAGE_IN_USE: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(false);
+
+impl TemporaryPage {
+    /// Map `frame` onto the scratch virtual page in the active table.
+    fn map(frame: Frame) -> Self {
+        use core::sync::atomic::Ordering;
+        assert!(
+            !TEMPORARY_PAGE_IN_USE.swap(true, Ordering::Acquire),
+            "TemporaryPage is not reentrant"
+        );
+        active_table().map(TEMPORARY_PAGE_ADDR, frame.start_address().as_usize());
+        TemporaryPage {
+            addr: TEMPORARY_PAGE_ADDR,
+        }
+    }
+
+    /// View the scratch page as a page table.
+    fn table(&mut self) -> &'static mut RvPageTable {
+        unsafe { &mut *(self.addr as *mut RvPageTable) }
+    }
+}
+
+impl Drop for TemporaryPage {
+    fn drop(&mut self) {
+        active_table().unmap(self.addr);
+        TEMPORARY_PAGE_IN_USE.store(false, core::sync::atomic::Ordering::Release);
     }
-    fn set_mmio(&mut self, _value: u8) {}
 }
 
 #[derive(Debug)]
@@ -221,23 +458,19 @@ impl InactivePageTable for InactivePageTable0 {
     fn new_bare() -> Self {
         let target = alloc_frame().expect("failed to allocate frame");
         let frame = Frame::of_addr(PhysAddr::new(target));
-        #[cfg(arch = "riscv32")]
-        unsafe {
-            let table = unsafe { &mut *(target as *mut RvPageTable) };
-            table.zero();
-        }
-        #[cfg(arch = "riscv64")]
-        active_table().with_temporary_map(target, |_, table: &mut RvPageTable| {
-            table.zero();
-            table.set_recursive(RECURSIVE_INDEX, frame.clone());
-        });
+        let mut temp = TemporaryPage::map(frame.clone());
+        let table = temp.table();
+        table.zero();
+        #[cfg(target_arch = "riscv64")]
+        table.set_recursive(RECURSIVE_INDEX, frame.clone());
         InactivePageTable0 { root_frame: frame }
     }
 
     #[cfg(target_arch = "riscv32")]
     fn map_kernel(&mut self) {
         info!("mapping kernel linear mapping");
-        let table: &mut RvPageTable = unsafe { self.root_frame.as_kernel_mut(LINEAR_OFFSET)};
+        let mut temp = TemporaryPage::map(self.root_frame.clone());
+        let table = temp.table();
         for i in 256..1024 {
             let flags = EF::VALID | EF::READABLE | EF::WRITABLE | EF::EXECUTABLE;
             let frame = Frame::of_addr(PhysAddr::new((i - 256) << 22));
@@ -267,7 +500,9 @@ impl InactivePageTable for InactivePageTable0 {
         satp.set_bits(44..60, 0); // AS is 0
         #[cfg(feature = "sv39")]
         satp.set_bits(60..64, satp::Mode::Sv39 as usize);
-        #[cfg(not(feature = "sv39"))]
+        #[cfg(feature = "sv57")]
+        satp.set_bits(60..64, 10); // Sv57
+        #[cfg(not(any(feature = "sv39", feature = "sv57")))]
         satp.set_bits(60..64, satp::Mode::Sv48 as usize);
         satp
     }
@@ -294,7 +529,11 @@ impl InactivePageTable for InactivePageTable0 {
                 Self::active_token(),
                 self.token()
             );
-            let mut active = unsafe { ActivePageTable(self.token(), ::core::mem::uninitialized()) };
+            // Map the inactive root through the scratch page and drive an
+            // `ActivePageTable` whose root is that scratch mapping; sub-tables
+            // remain reachable through the linear kernel mapping.
+            let mut temp = TemporaryPage::map(self.root_frame.clone());
+            let mut active = ActivePageTable(temp.addr, None);
 
             let ret = f(&mut active);
             debug!("finish table");
@@ -305,28 +544,27 @@ impl InactivePageTable for InactivePageTable0 {
         }
         #[cfg(target_arch = "riscv64")]
         {
-            let target = satp::read().frame().start_address().as_usize();
-            active_table().with_temporary_map(target, |active_table, root_table: &mut RvPageTable| {
-                let backup = root_table[RECURSIVE_INDEX].clone();
-
-                // overwrite recursive mapping
-                root_table[RECURSIVE_INDEX].set(self.root_frame.clone(), EF::VALID);
-                unsafe {
-                    sfence_vma_all();
-                }
-
-                // execute f in the new context
-                let ret = f(active_table);
-
-                // restore recursive mapping to original p2 table
-                root_table[RECURSIVE_INDEX] = backup;
-                unsafe {
-                    sfence_vma_all();
-                }
-
-                ret
-            })
+            // Map the *active* root through the scratch page and redirect its
+            // recursive slot at the inactive root, so the active table's
+            // recursive walk lands in the inactive tree while `f` runs.
+            let active_root = satp::read().frame();
+            let mut temp = TemporaryPage::map(active_root);
+            let root_table = temp.table();
+            let backup = root_table[RECURSIVE_INDEX].clone();
+
+            root_table[RECURSIVE_INDEX].set(self.root_frame.clone(), EF::VALID);
+            unsafe {
+                sfence_vma_all();
+            }
+
+            let ret = f(&mut active_table());
+
+            root_table[RECURSIVE_INDEX] = backup;
+            unsafe {
+                sfence_vma_all();
+            }
 
+            ret
         }
     }
 }